@@ -1,22 +1,45 @@
 use anyhow::{Context, Result};
 use arrow::array::{
-    ArrayRef, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder,
+    ArrayRef, BooleanBuilder, Float64Builder, Int64Builder, MapBuilder, StringBuilder,
     TimestampMillisecondBuilder, UInt32Builder,
 };
 use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
 use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
 use bzip2::read::MultiBzDecoder;
+use chrono::Datelike;
 use clap::Parser;
-use parquet::arrow::ArrowWriter;
+use crossbeam_channel::{Receiver, Sender};
+use parquet::arrow::async_writer::AsyncArrowWriter;
 use parquet::basic::Compression;
 use parquet::file::properties::WriterProperties;
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::name::QName;
 use quick_xml::Reader;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
 use std::sync::Arc;
 
+/// How many finished batches the parser is allowed to get ahead of the writer.
+/// This caps peak memory use and provides the back-pressure that keeps a slow
+/// writer from forcing the parser to buffer unboundedly.
+const CHANNEL_DEPTH: usize = 4;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Parquet,
+    Csv,
+    Jsonl,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PartitionBy {
+    Year,
+    Month,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -24,10 +47,14 @@ struct Args {
     #[arg(short, long)]
     input: String,
 
-    /// Output Parquet file
+    /// Output file (or directory, with --partition-by)
     #[arg(short, long)]
     output: String,
 
+    /// Output format
+    #[arg(short = 'f', long, value_enum, default_value = "parquet")]
+    format: OutputFormat,
+
     /// Batch size for writing records
     #[arg(short, long, default_value_t = 100000)]
     batch_size: usize,
@@ -35,6 +62,41 @@ struct Args {
     /// Continue processing on parse errors (saves what was successfully parsed)
     #[arg(long, default_value_t = false)]
     continue_on_error: bool,
+
+    /// In-flight bytes buffered by the async writer before a flush is forced (Parquet without --partition-by only)
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    write_buffer_size: usize,
+
+    /// Hive-partition the Parquet output by changeset month/year; --output becomes a directory
+    #[arg(long, value_enum)]
+    partition_by: Option<PartitionBy>,
+
+    /// Only include changesets whose bbox intersects minlon,minlat,maxlon,maxlat
+    #[arg(long)]
+    bbox: Option<String>,
+
+    /// Only include changesets created at or after this RFC3339 timestamp
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Only include changesets created before this RFC3339 timestamp
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Only include changesets from this uid (repeatable). If --user is also
+    /// given, a changeset matching either allow-list is included (OR, not AND)
+    #[arg(long = "uid")]
+    uids: Vec<i64>,
+
+    /// Only include changesets from this user (repeatable). If --uid is also
+    /// given, a changeset matching either allow-list is included (OR, not AND)
+    #[arg(long = "user")]
+    users: Vec<String>,
+
+    /// Skip changesets with id < N, e.g. to resume after a failed run (see the
+    /// `max_id` in the output's sidecar manifest)
+    #[arg(long)]
+    min_id: Option<i64>,
 }
 
 #[derive(Debug, Default)]
@@ -52,6 +114,7 @@ struct Changeset {
     num_changes: u32,
     comments_count: u32,
     description: Option<String>,
+    tags: Vec<(String, String)>,
 }
 
 struct BatchBuilders {
@@ -68,6 +131,7 @@ struct BatchBuilders {
     num_changes: UInt32Builder,
     comments_count: UInt32Builder,
     description: StringBuilder,
+    tags: MapBuilder<StringBuilder, StringBuilder>,
     len: usize,
 }
 
@@ -90,11 +154,16 @@ impl BatchBuilders {
             num_changes: UInt32Builder::with_capacity(capacity),
             comments_count: UInt32Builder::with_capacity(capacity),
             description: StringBuilder::with_capacity(capacity, string_byte_capacity),
+            tags: MapBuilder::new(
+                None,
+                StringBuilder::with_capacity(capacity, string_byte_capacity),
+                StringBuilder::with_capacity(capacity, string_byte_capacity),
+            ),
             len: 0,
         }
     }
 
-    fn append(&mut self, cs: &Changeset) {
+    fn append(&mut self, cs: &Changeset) -> Result<()> {
         self.id.append_value(cs.id);
 
         if let Some(ts) = cs.created_at {
@@ -156,7 +225,17 @@ impl BatchBuilders {
             self.description.append_null();
         }
 
+        for (k, v) in &cs.tags {
+            self.tags.keys().append_value(k);
+            self.tags.values().append_value(v);
+        }
+        // `append(true)` always marks the row valid; with no keys pushed since
+        // the last call this produces a non-null, zero-length map rather than
+        // a null cell, which is what an untagged changeset should round-trip to.
+        self.tags.append(true)?;
+
         self.len += 1;
+        Ok(())
     }
 
     fn len(&self) -> usize {
@@ -182,6 +261,7 @@ impl BatchBuilders {
             Arc::new(self.num_changes.finish()),
             Arc::new(self.comments_count.finish()),
             Arc::new(self.description.finish()),
+            Arc::new(self.tags.finish()),
         ];
 
         let batch = RecordBatch::try_new(schema.clone(), columns)?;
@@ -196,6 +276,103 @@ fn parse_timestamp(s: &str) -> Result<i64> {
     Ok(dt.timestamp_millis())
 }
 
+/// A predicate evaluated against every parsed changeset before it's handed
+/// to the sink, so a region or time-window extract never has to materialize
+/// the full conversion first. An empty `Filter` (the default from no CLI
+/// flags) matches everything.
+#[derive(Debug, Default)]
+struct Filter {
+    min_id: Option<i64>,
+    bbox: Option<(f64, f64, f64, f64)>,
+    since: Option<i64>,
+    until: Option<i64>,
+    uids: std::collections::HashSet<i64>,
+    users: std::collections::HashSet<String>,
+}
+
+impl Filter {
+    fn from_args(args: &Args) -> Result<Self> {
+        let bbox = args
+            .bbox
+            .as_deref()
+            .map(parse_bbox)
+            .transpose()
+            .context("Failed to parse --bbox")?;
+        let since = args.since.as_deref().map(parse_timestamp).transpose()?;
+        let until = args.until.as_deref().map(parse_timestamp).transpose()?;
+
+        Ok(Self {
+            min_id: args.min_id,
+            bbox,
+            since,
+            until,
+            uids: args.uids.iter().copied().collect(),
+            users: args.users.iter().cloned().collect(),
+        })
+    }
+
+    fn matches(&self, cs: &Changeset) -> bool {
+        if self.min_id.is_some_and(|min_id| cs.id < min_id) {
+            return false;
+        }
+
+        if let Some((min_lon, min_lat, max_lon, max_lat)) = self.bbox {
+            match (cs.min_lon, cs.min_lat, cs.max_lon, cs.max_lat) {
+                (Some(cs_min_lon), Some(cs_min_lat), Some(cs_max_lon), Some(cs_max_lat)) => {
+                    let intersects = cs_min_lon <= max_lon
+                        && cs_max_lon >= min_lon
+                        && cs_min_lat <= max_lat
+                        && cs_max_lat >= min_lat;
+                    if !intersects {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            match cs.created_at {
+                Some(created_at) => {
+                    if self.since.is_some_and(|since| created_at < since) {
+                        return false;
+                    }
+                    if self.until.is_some_and(|until| created_at >= until) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+
+        if !self.uids.is_empty() || !self.users.is_empty() {
+            let uid_matches = cs.uid.is_some_and(|uid| self.uids.contains(&uid));
+            let user_matches = cs
+                .user
+                .as_ref()
+                .is_some_and(|user| self.users.contains(user));
+            if !(uid_matches || user_matches) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn parse_bbox(s: &str) -> Result<(f64, f64, f64, f64)> {
+    let parts: Vec<&str> = s.split(',').collect();
+    let [min_lon, min_lat, max_lon, max_lat] = parts.as_slice() else {
+        anyhow::bail!("Expected bbox as minlon,minlat,maxlon,maxlat, got: {}", s);
+    };
+    Ok((
+        min_lon.trim().parse()?,
+        min_lat.trim().parse()?,
+        max_lon.trim().parse()?,
+        max_lat.trim().parse()?,
+    ))
+}
+
 fn parse_changeset_element(e: &BytesStart) -> Result<Changeset> {
     let mut changeset = Changeset::default();
 
@@ -239,8 +416,9 @@ fn apply_changeset_tag(e: &BytesStart, changeset: &mut Changeset) -> Result<()>
 
     if let (Some(k), Some(v)) = (key, value) {
         if k == "comment" {
-            changeset.description = Some(v);
+            changeset.description = Some(v.clone());
         }
+        changeset.tags.push((k, v));
     }
 
     Ok(())
@@ -283,13 +461,29 @@ fn parse_changeset_body<R: std::io::BufRead>(
     Ok(())
 }
 
-fn parse_and_write_changesets<R: std::io::Read>(
+/// Running totals over every changeset actually written (post-filter), used
+/// to populate the sidecar resume manifest.
+#[derive(Debug, Default)]
+struct ParseStats {
+    count: usize,
+    skipped: usize,
+    min_id: Option<i64>,
+    max_id: Option<i64>,
+    min_created_at: Option<i64>,
+    max_created_at: Option<i64>,
+}
+
+/// Parses `reader` on the calling thread and sends each finished batch of
+/// `Changeset`s across `tx`, where a separate thread drains it into the
+/// selected `ChangesetSink`. The channel is bounded, so this call blocks
+/// (providing back-pressure) whenever the sink falls behind.
+fn parse_and_send_changesets<R: std::io::Read>(
     reader: R,
-    writer: &mut ArrowWriter<File>,
-    schema: &Arc<Schema>,
+    tx: &Sender<Vec<Changeset>>,
     batch_size: usize,
     continue_on_error: bool,
-) -> Result<usize> {
+    filter: &Filter,
+) -> Result<ParseStats> {
     // Use a large buffer (1MB) to avoid splitting XML tags across read boundaries
     let buffered_reader = BufReader::with_capacity(1024 * 1024, reader);
     let mut xml_reader = Reader::from_reader(buffered_reader);
@@ -299,28 +493,47 @@ fn parse_and_write_changesets<R: std::io::Read>(
     xml_reader.config_mut().check_comments = false; // Don't validate comments
 
     let mut buf = Vec::new();
-    let mut count = 0;
     let mut batch_num = 0;
     let mut last_changeset_id = 0i64;
+    let mut stats = ParseStats::default();
 
     let mut temp_buf = Vec::new();
     let effective_batch_size = batch_size.max(1);
-    let mut builders = BatchBuilders::with_capacity(effective_batch_size);
+    let mut batch = Vec::with_capacity(effective_batch_size);
 
     let mut process_changeset = |changeset: Changeset| -> Result<()> {
         last_changeset_id = changeset.id;
-        builders.append(&changeset);
-        count += 1;
 
-        if builders.len() >= effective_batch_size {
+        if !filter.matches(&changeset) {
+            stats.skipped += 1;
+            return Ok(());
+        }
+
+        stats.min_id = Some(stats.min_id.map_or(changeset.id, |v| v.min(changeset.id)));
+        stats.max_id = Some(stats.max_id.map_or(changeset.id, |v| v.max(changeset.id)));
+        if let Some(created_at) = changeset.created_at {
+            stats.min_created_at =
+                Some(stats.min_created_at.map_or(created_at, |v| v.min(created_at)));
+            stats.max_created_at =
+                Some(stats.max_created_at.map_or(created_at, |v| v.max(created_at)));
+        }
+
+        batch.push(changeset);
+        stats.count += 1;
+
+        if batch.len() >= effective_batch_size {
             batch_num += 1;
-            let batch = builders.finish_batch(schema)?;
-            let rows = batch.num_rows();
             println!(
-                "Writing batch {} with {} rows (total: {})...",
-                batch_num, rows, count
+                "Parsed batch {} with {} rows (total: {})...",
+                batch_num,
+                batch.len(),
+                stats.count
             );
-            writer.write(&batch)?;
+            tx.send(std::mem::replace(
+                &mut batch,
+                Vec::with_capacity(effective_batch_size),
+            ))
+            .map_err(|_| anyhow::anyhow!("Writer thread closed its end of the channel"))?;
         }
 
         Ok(())
@@ -344,7 +557,7 @@ fn parse_and_write_changesets<R: std::io::Read>(
             Err(e) => {
                 eprintln!("\n=== XML PARSE ERROR ===");
                 eprintln!("Position: {}", position);
-                eprintln!("Changesets processed: {}", count);
+                eprintln!("Changesets processed: {}", stats.count);
                 eprintln!("Last changeset ID: {}", last_changeset_id);
                 eprintln!("Error: {}", e);
                 eprintln!("\nBuffer content at error (first 500 bytes):");
@@ -355,7 +568,10 @@ fn parse_and_write_changesets<R: std::io::Read>(
                 eprintln!("======================\n");
 
                 if continue_on_error {
-                    eprintln!("Continuing with {} successfully parsed changesets...", count);
+                    eprintln!(
+                        "Continuing with {} successfully parsed changesets...",
+                        stats.count
+                    );
                     break;
                 } else {
                     return Err(anyhow::anyhow!("Error parsing XML: {}. Use --continue-on-error to save partial results.", e));
@@ -367,18 +583,452 @@ fn parse_and_write_changesets<R: std::io::Read>(
     }
 
     // Process remaining changesets
-    if !builders.is_empty() {
+    if !batch.is_empty() {
         batch_num += 1;
-        let batch = builders.finish_batch(schema)?;
-        let rows = batch.num_rows();
         println!(
-            "Writing final batch {} with {} rows (total: {})...",
-            batch_num, rows, count
+            "Parsed final batch {} with {} rows (total: {})...",
+            batch_num,
+            batch.len(),
+            stats.count
         );
-        writer.write(&batch)?;
+        tx.send(batch)
+            .map_err(|_| anyhow::anyhow!("Writer thread closed its end of the channel"))?;
+    }
+
+    Ok(stats)
+}
+
+/// Owns the `Reader`, doing all XML parsing and batch assembly on a dedicated
+/// thread so bzip2 decompression and XML parsing overlap with sink encoding
+/// on the writer thread instead of serializing against it.
+fn spawn_parser(
+    input_path: String,
+    tx: Sender<Vec<Changeset>>,
+    batch_size: usize,
+    continue_on_error: bool,
+    filter: Filter,
+) -> std::thread::JoinHandle<Result<ParseStats>> {
+    std::thread::spawn(move || {
+        let file = File::open(&input_path)
+            .with_context(|| format!("Failed to open input file: {}", input_path))?;
+
+        if input_path.ends_with(".bz2") {
+            println!("Detected bzip2 multi-stream compressed file");
+            let decoder = MultiBzDecoder::new(file);
+            parse_and_send_changesets(decoder, &tx, batch_size, continue_on_error, &filter)
+        } else {
+            parse_and_send_changesets(file, &tx, batch_size, continue_on_error, &filter)
+        }
+    })
+}
+
+/// A destination for parsed changesets, decoupling the parser/writer pipeline
+/// from any single output encoding. `write_batch` is called once per batch
+/// that comes off the channel; `finish` is called exactly once after the
+/// channel closes.
+#[async_trait]
+trait ChangesetSink: Send {
+    async fn write_batch(&mut self, batch: &[Changeset]) -> Result<()>;
+    async fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Builds `RecordBatch`es from each incoming batch and streams them through
+/// an `AsyncArrowWriter`, so the encode/flush of one row group can overlap
+/// with the parser producing the next.
+struct ParquetSink {
+    writer: AsyncArrowWriter<tokio::fs::File>,
+    schema: Arc<Schema>,
+    write_buffer_size: usize,
+    buffered_bytes: usize,
+}
+
+impl ParquetSink {
+    fn try_new(output_path: &str, schema: Arc<Schema>, write_buffer_size: usize) -> Result<Self> {
+        let output_file = File::create(output_path)
+            .with_context(|| format!("Failed to create output file: {}", output_path))?;
+        let output_file = tokio::fs::File::from_std(output_file);
+
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+
+        let writer = AsyncArrowWriter::try_new(output_file, schema.clone(), Some(props))?;
+
+        Ok(Self {
+            writer,
+            schema,
+            write_buffer_size,
+            buffered_bytes: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl ChangesetSink for ParquetSink {
+    async fn write_batch(&mut self, batch: &[Changeset]) -> Result<()> {
+        let mut builders = BatchBuilders::with_capacity(batch.len());
+        for cs in batch {
+            builders.append(cs)?;
+        }
+        let record_batch = builders.finish_batch(&self.schema)?;
+
+        self.buffered_bytes += record_batch.get_array_memory_size();
+        self.writer.write(&record_batch).await?;
+
+        if self.buffered_bytes >= self.write_buffer_size {
+            self.writer.flush().await?;
+            self.buffered_bytes = 0;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<()> {
+        self.writer.close().await?;
+        Ok(())
+    }
+}
+
+/// Which Hive-style bucket a changeset's `created_at` falls into. Changesets
+/// with no `created_at` go to a dedicated `__null__` bucket rather than being
+/// dropped, since OSM changeset dumps can have uncommon but valid gaps.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum PartitionKey {
+    Year(i32),
+    YearMonth(i32, u32),
+    Null,
+}
+
+impl PartitionKey {
+    fn for_changeset(granularity: PartitionBy, cs: &Changeset) -> Self {
+        let Some(ms) = cs.created_at else {
+            return PartitionKey::Null;
+        };
+        let Some(dt) = chrono::DateTime::from_timestamp_millis(ms) else {
+            return PartitionKey::Null;
+        };
+
+        match granularity {
+            PartitionBy::Year => PartitionKey::Year(dt.year()),
+            PartitionBy::Month => PartitionKey::YearMonth(dt.year(), dt.month()),
+        }
+    }
+
+    fn dir_name(&self) -> String {
+        match self {
+            PartitionKey::Year(y) => format!("created_at_year={}", y),
+            PartitionKey::YearMonth(y, m) => {
+                format!("created_at_year={}/created_at_month={:02}", y, m)
+            }
+            PartitionKey::Null => "created_at_year=__null__".to_string(),
+        }
+    }
+}
+
+struct PartitionBucket {
+    writer: AsyncArrowWriter<tokio::fs::File>,
+    builders: BatchBuilders,
+}
+
+/// Routes each changeset to an `AsyncArrowWriter` for its year/month bucket,
+/// writing one Hive-style `created_at_year=YYYY/created_at_month=MM/part-0.parquet`
+/// file per bucket (`created_at_year=YYYY/part-0.parquet` when partitioning by
+/// year) under `output_dir` so DuckDB/Spark can prune partitions at query time.
+struct PartitionedParquetSink {
+    output_dir: String,
+    schema: Arc<Schema>,
+    granularity: PartitionBy,
+    batch_size: usize,
+    buckets: HashMap<PartitionKey, PartitionBucket>,
+}
+
+impl PartitionedParquetSink {
+    fn new(
+        output_dir: &str,
+        schema: Arc<Schema>,
+        granularity: PartitionBy,
+        batch_size: usize,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create output directory: {}", output_dir))?;
+
+        Ok(Self {
+            output_dir: output_dir.to_string(),
+            schema,
+            granularity,
+            batch_size: batch_size.max(1),
+            buckets: HashMap::new(),
+        })
+    }
+
+    fn bucket_for(&mut self, key: PartitionKey) -> Result<&mut PartitionBucket> {
+        if !self.buckets.contains_key(&key) {
+            let dir = Path::new(&self.output_dir).join(key.dir_name());
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create partition directory: {:?}", dir))?;
+
+            let path = dir.join("part-0.parquet");
+            let file = File::create(&path)
+                .with_context(|| format!("Failed to create partition file: {:?}", path))?;
+            let file = tokio::fs::File::from_std(file);
+
+            let props = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer = AsyncArrowWriter::try_new(file, self.schema.clone(), Some(props))?;
+
+            self.buckets.insert(
+                key,
+                PartitionBucket {
+                    writer,
+                    builders: BatchBuilders::with_capacity(self.batch_size),
+                },
+            );
+        }
+
+        Ok(self.buckets.get_mut(&key).expect("bucket just inserted"))
+    }
+}
+
+#[async_trait]
+impl ChangesetSink for PartitionedParquetSink {
+    async fn write_batch(&mut self, batch: &[Changeset]) -> Result<()> {
+        for cs in batch {
+            let key = PartitionKey::for_changeset(self.granularity, cs);
+            let schema = self.schema.clone();
+            let bucket = self.bucket_for(key)?;
+            bucket.builders.append(cs)?;
+
+            if bucket.builders.len() >= self.batch_size {
+                let record_batch = bucket.builders.finish_batch(&schema)?;
+                bucket.writer.write(&record_batch).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<()> {
+        for (_, mut bucket) in self.buckets {
+            if !bucket.builders.is_empty() {
+                let record_batch = bucket.builders.finish_batch(&self.schema)?;
+                bucket.writer.write(&record_batch).await?;
+            }
+            bucket.writer.close().await?;
+        }
+        Ok(())
+    }
+}
+
+fn format_timestamp(ms: Option<i64>) -> String {
+    ms.and_then(|ms| chrono::DateTime::from_timestamp_millis(ms))
+        .map(|dt| dt.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Sidecar written next to the output recording the id/timestamp range that
+/// was actually written. It is written only when a run ends without error,
+/// including after an early `--continue-on-error` break. A later invocation
+/// can pass `max_id` as `--min-id` to resume: changesets with a lower id are
+/// still parsed but not written, while the changeset with id `max_id` itself
+/// is written again.
+#[derive(serde::Serialize)]
+struct ResumeManifest {
+    min_id: Option<i64>,
+    max_id: Option<i64>,
+    count: usize,
+    min_created_at: Option<String>,
+    max_created_at: Option<String>,
+}
+
+fn write_manifest(output_path: &str, stats: &ParseStats) -> Result<()> {
+    let manifest = ResumeManifest {
+        min_id: stats.min_id,
+        max_id: stats.max_id,
+        count: stats.count,
+        min_created_at: stats.min_created_at.map(|ms| format_timestamp(Some(ms))),
+        max_created_at: stats.max_created_at.map(|ms| format_timestamp(Some(ms))),
+    };
+
+    let manifest_path = format!("{}.manifest.json", output_path.trim_end_matches('/'));
+    let file = File::create(&manifest_path)
+        .with_context(|| format!("Failed to create manifest file: {}", manifest_path))?;
+    serde_json::to_writer_pretty(file, &manifest)
+        .with_context(|| format!("Failed to write manifest file: {}", manifest_path))?;
+
+    Ok(())
+}
+
+fn write_csv_field(out: &mut impl Write, field: &str) -> Result<()> {
+    if field.contains(['"', ',', '\n', '\r']) {
+        write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+    } else {
+        write!(out, "{}", field)?;
+    }
+    Ok(())
+}
+
+/// Writes a header row followed by one CSV line per changeset. Only the
+/// free-text `user` and `description` fields need RFC4180 quoting in
+/// practice, so this hand-rolls that instead of pulling in a CSV crate for a
+/// handful of columns.
+struct CsvSink {
+    writer: BufWriter<File>,
+}
+
+impl CsvSink {
+    fn new(output_path: &str) -> Result<Self> {
+        let file = File::create(output_path)
+            .with_context(|| format!("Failed to create output file: {}", output_path))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(
+            writer,
+            "id,created_at,closed_at,open,user,uid,min_lat,min_lon,max_lat,max_lon,num_changes,comments_count,description"
+        )?;
+        Ok(Self { writer })
+    }
+}
+
+#[async_trait]
+impl ChangesetSink for CsvSink {
+    async fn write_batch(&mut self, batch: &[Changeset]) -> Result<()> {
+        for cs in batch {
+            write!(self.writer, "{},", cs.id)?;
+            write!(self.writer, "{},", format_timestamp(cs.created_at))?;
+            write!(self.writer, "{},", format_timestamp(cs.closed_at))?;
+            write!(self.writer, "{},", cs.open)?;
+            write_csv_field(&mut self.writer, cs.user.as_deref().unwrap_or(""))?;
+            write!(self.writer, ",")?;
+            write!(
+                self.writer,
+                "{},",
+                cs.uid.map(|uid| uid.to_string()).unwrap_or_default()
+            )?;
+            write!(
+                self.writer,
+                "{},",
+                cs.min_lat.map(|v| v.to_string()).unwrap_or_default()
+            )?;
+            write!(
+                self.writer,
+                "{},",
+                cs.min_lon.map(|v| v.to_string()).unwrap_or_default()
+            )?;
+            write!(
+                self.writer,
+                "{},",
+                cs.max_lat.map(|v| v.to_string()).unwrap_or_default()
+            )?;
+            write!(
+                self.writer,
+                "{},",
+                cs.max_lon.map(|v| v.to_string()).unwrap_or_default()
+            )?;
+            write!(self.writer, "{},", cs.num_changes)?;
+            write!(self.writer, "{},", cs.comments_count)?;
+            write_csv_field(&mut self.writer, cs.description.as_deref().unwrap_or(""))?;
+            writeln!(self.writer)?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct JsonlSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonlSink {
+    fn new(output_path: &str) -> Result<Self> {
+        let file = File::create(output_path)
+            .with_context(|| format!("Failed to create output file: {}", output_path))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+fn changeset_to_json(cs: &Changeset) -> serde_json::Value {
+    let mut obj = serde_json::Map::new();
+    obj.insert("id".into(), serde_json::json!(cs.id));
+    if let Some(ts) = cs.created_at {
+        obj.insert("created_at".into(), serde_json::json!(format_timestamp(Some(ts))));
+    }
+    if let Some(ts) = cs.closed_at {
+        obj.insert("closed_at".into(), serde_json::json!(format_timestamp(Some(ts))));
+    }
+    obj.insert("open".into(), serde_json::json!(cs.open));
+    if let Some(ref user) = cs.user {
+        obj.insert("user".into(), serde_json::json!(user));
+    }
+    if let Some(uid) = cs.uid {
+        obj.insert("uid".into(), serde_json::json!(uid));
+    }
+    if let Some(v) = cs.min_lat {
+        obj.insert("min_lat".into(), serde_json::json!(v));
+    }
+    if let Some(v) = cs.min_lon {
+        obj.insert("min_lon".into(), serde_json::json!(v));
+    }
+    if let Some(v) = cs.max_lat {
+        obj.insert("max_lat".into(), serde_json::json!(v));
+    }
+    if let Some(v) = cs.max_lon {
+        obj.insert("max_lon".into(), serde_json::json!(v));
+    }
+    obj.insert("num_changes".into(), serde_json::json!(cs.num_changes));
+    obj.insert("comments_count".into(), serde_json::json!(cs.comments_count));
+    if let Some(ref description) = cs.description {
+        obj.insert("description".into(), serde_json::json!(description));
+    }
+    // Always present, as `{}` for an untagged changeset: an empty map is not
+    // null, and the Parquet sink round-trips untagged changesets to a
+    // non-null empty map too (see `BatchBuilders::append`).
+    let tags: serde_json::Map<String, serde_json::Value> = cs
+        .tags
+        .iter()
+        .map(|(k, v)| (k.clone(), serde_json::json!(v)))
+        .collect();
+    obj.insert("tags".into(), serde_json::Value::Object(tags));
+    serde_json::Value::Object(obj)
+}
+
+#[async_trait]
+impl ChangesetSink for JsonlSink {
+    async fn write_batch(&mut self, batch: &[Changeset]) -> Result<()> {
+        for cs in batch {
+            serde_json::to_writer(&mut self.writer, &changeset_to_json(cs))?;
+            writeln!(self.writer)?;
+        }
+        Ok(())
     }
 
-    Ok(count)
+    async fn finish(mut self: Box<Self>) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Drains finished batches from `rx` into `sink`. Runs on a single-threaded
+/// Tokio runtime: the Parquet sink's writes are genuinely async, while the
+/// CSV/JSON sinks simply don't await anything, so one runtime serves all
+/// three without extra machinery.
+fn write_changesets(rx: Receiver<Vec<Changeset>>, sink: Box<dyn ChangesetSink>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start writer runtime")?;
+
+    runtime.block_on(async {
+        let mut sink = sink;
+        while let Ok(batch) = rx.recv() {
+            sink.write_batch(&batch).await?;
+        }
+        sink.finish().await
+    })
 }
 
 fn create_schema() -> Arc<Schema> {
@@ -404,6 +1054,24 @@ fn create_schema() -> Arc<Schema> {
         Field::new("num_changes", DataType::UInt32, false),
         Field::new("comments_count", DataType::UInt32, false),
         Field::new("description", DataType::Utf8, true),
+        Field::new(
+            "tags",
+            DataType::Map(
+                Arc::new(Field::new(
+                    "entries",
+                    DataType::Struct(
+                        vec![
+                            Field::new("keys", DataType::Utf8, false),
+                            Field::new("values", DataType::Utf8, true),
+                        ]
+                        .into(),
+                    ),
+                    false,
+                )),
+                false,
+            ),
+            true,
+        ),
     ]))
 }
 
@@ -413,44 +1081,421 @@ fn main() -> Result<()> {
     println!("Reading from: {}", args.input);
     println!("Writing to: {}", args.output);
 
-    // Set up parquet writer first
-    let output_file = File::create(&args.output)
-        .with_context(|| format!("Failed to create output file: {}", args.output))?;
-
-    let props = WriterProperties::builder()
-        .set_compression(Compression::SNAPPY)
-        .build();
+    let filter = Filter::from_args(&args)?;
+    let (tx, rx) = crossbeam_channel::bounded::<Vec<Changeset>>(CHANNEL_DEPTH);
 
-    let schema = create_schema();
-    let mut writer = ArrowWriter::try_new(output_file, schema.clone(), Some(props))?;
+    let parser = spawn_parser(
+        args.input.clone(),
+        tx,
+        args.batch_size,
+        args.continue_on_error,
+        filter,
+    );
 
-    // Stream parse and write
-    let file = File::open(&args.input)
-        .with_context(|| format!("Failed to open input file: {}", args.input))?;
-
-    let total_count = if args.input.ends_with(".bz2") {
-        println!("Detected bzip2 multi-stream compressed file");
-        let decoder = MultiBzDecoder::new(file);
-        parse_and_write_changesets(
-            decoder,
-            &mut writer,
-            &schema,
+    let sink: Box<dyn ChangesetSink> = match (args.format, args.partition_by) {
+        (OutputFormat::Parquet, Some(granularity)) => Box::new(PartitionedParquetSink::new(
+            &args.output,
+            create_schema(),
+            granularity,
             args.batch_size,
-            args.continue_on_error,
-        )?
-    } else {
-        parse_and_write_changesets(
-            file,
-            &mut writer,
-            &schema,
-            args.batch_size,
-            args.continue_on_error,
-        )?
+        )?),
+        (OutputFormat::Parquet, None) => Box::new(ParquetSink::try_new(
+            &args.output,
+            create_schema(),
+            args.write_buffer_size,
+        )?),
+        (OutputFormat::Csv, None) => Box::new(CsvSink::new(&args.output)?),
+        (OutputFormat::Jsonl, None) => Box::new(JsonlSink::new(&args.output)?),
+        (_, Some(_)) => {
+            anyhow::bail!("--partition-by is only supported with --format parquet")
+        }
     };
 
-    writer.close()?;
+    write_changesets(rx, sink)?;
+
+    let stats = parser
+        .join()
+        .map_err(|_| anyhow::anyhow!("Parser thread panicked"))??;
+
+    write_manifest(&args.output, &stats)?;
 
-    println!("Successfully wrote {} changesets to {}", total_count, args.output);
+    println!(
+        "Successfully wrote {} changesets ({} skipped by filters) to {}",
+        stats.count, stats.skipped, args.output
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{MapArray, StringArray};
+
+    fn changeset(id: i64, tags: Vec<(&str, &str)>) -> Changeset {
+        Changeset {
+            id,
+            tags: tags
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn untagged_changeset_round_trips_to_non_null_empty_map() {
+        let schema = create_schema();
+        let mut builders = BatchBuilders::with_capacity(1);
+        builders.append(&changeset(1, vec![])).unwrap();
+        let batch = builders.finish_batch(&schema).unwrap();
+
+        let tags = batch
+            .column_by_name("tags")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<MapArray>()
+            .unwrap();
+
+        assert!(!tags.is_null(0));
+        assert_eq!(tags.value_length(0), 0);
+    }
+
+    #[test]
+    fn tagged_changeset_round_trips_keys_and_values() {
+        let schema = create_schema();
+        let mut builders = BatchBuilders::with_capacity(1);
+        builders
+            .append(&changeset(1, vec![("source", "Bing"), ("comment", "fix road")]))
+            .unwrap();
+        let batch = builders.finish_batch(&schema).unwrap();
+
+        let tags = batch
+            .column_by_name("tags")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<MapArray>()
+            .unwrap();
+
+        assert!(!tags.is_null(0));
+        assert_eq!(tags.value_length(0), 2);
+
+        let entries = tags.value(0);
+        let keys = entries
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let values = entries
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+
+        assert_eq!(keys.value(0), "source");
+        assert_eq!(values.value(0), "Bing");
+        assert_eq!(keys.value(1), "comment");
+        assert_eq!(values.value(1), "fix road");
+    }
+
+    fn changeset_in_bbox(min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> Changeset {
+        Changeset {
+            min_lon: Some(min_lon),
+            min_lat: Some(min_lat),
+            max_lon: Some(max_lon),
+            max_lat: Some(max_lat),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bbox_filter_matches_intersecting_changeset() {
+        let filter = Filter {
+            bbox: Some((0.0, 0.0, 10.0, 10.0)),
+            ..Default::default()
+        };
+        // Overlaps the requested box even though it isn't fully contained.
+        let cs = changeset_in_bbox(5.0, 5.0, 15.0, 15.0);
+        assert!(filter.matches(&cs));
+    }
+
+    #[test]
+    fn bbox_filter_rejects_disjoint_changeset() {
+        let filter = Filter {
+            bbox: Some((0.0, 0.0, 10.0, 10.0)),
+            ..Default::default()
+        };
+        let cs = changeset_in_bbox(20.0, 20.0, 30.0, 30.0);
+        assert!(!filter.matches(&cs));
+    }
+
+    #[test]
+    fn bbox_filter_rejects_changeset_missing_coordinates() {
+        let filter = Filter {
+            bbox: Some((0.0, 0.0, 10.0, 10.0)),
+            ..Default::default()
+        };
+        let cs = Changeset::default();
+        assert!(!filter.matches(&cs));
+    }
+
+    #[test]
+    fn no_bbox_filter_matches_changeset_missing_coordinates() {
+        let filter = Filter::default();
+        let cs = Changeset::default();
+        assert!(filter.matches(&cs));
+    }
+
+    const SAMPLE_CHANGESETS_XML: &str = r#"<osm>
+<changeset id="1" created_at="2020-01-01T00:00:00Z" uid="10" user="alice" num_changes="1" comments_count="0"/>
+<changeset id="2" created_at="2020-01-02T00:00:00Z" uid="11" user="bob" num_changes="2" comments_count="0"/>
+<changeset id="3" created_at="2020-01-03T00:00:00Z" uid="12" user="carol" num_changes="3" comments_count="0"/>
+</osm>"#;
+
+    #[test]
+    fn parse_and_send_changesets_respects_batch_boundaries() {
+        let (tx, rx) = crossbeam_channel::bounded(8);
+        let filter = Filter::default();
+        let stats = parse_and_send_changesets(
+            SAMPLE_CHANGESETS_XML.as_bytes(),
+            &tx,
+            2,
+            false,
+            &filter,
+        )
+        .unwrap();
+        drop(tx);
+
+        let batches: Vec<Vec<Changeset>> = rx.iter().collect();
+        assert_eq!(batches.len(), 2, "3 changesets at batch_size=2 should flush a full batch then a remainder");
+        assert_eq!(batches[0].len(), 2);
+        assert_eq!(batches[1].len(), 1);
+        assert_eq!(
+            batches.iter().flatten().map(|cs| cs.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.skipped, 0);
+        assert_eq!(stats.min_id, Some(1));
+        assert_eq!(stats.max_id, Some(3));
+    }
+
+    #[test]
+    fn parse_and_send_changesets_counts_filtered_changesets_as_skipped() {
+        let (tx, rx) = crossbeam_channel::bounded(8);
+        let filter = Filter {
+            min_id: Some(2),
+            ..Default::default()
+        };
+        let stats = parse_and_send_changesets(
+            SAMPLE_CHANGESETS_XML.as_bytes(),
+            &tx,
+            10,
+            false,
+            &filter,
+        )
+        .unwrap();
+        drop(tx);
+
+        let batches: Vec<Vec<Changeset>> = rx.iter().collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            batches[0].iter().map(|cs| cs.id).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.min_id, Some(2));
+        assert_eq!(stats.max_id, Some(3));
+    }
+
+    #[test]
+    fn min_id_filter_excludes_ids_below_cutoff() {
+        let filter = Filter {
+            min_id: Some(5),
+            ..Default::default()
+        };
+
+        assert!(!filter.matches(&changeset(4, vec![])));
+        assert!(filter.matches(&changeset(5, vec![])));
+        assert!(filter.matches(&changeset(6, vec![])));
+    }
+
+    #[test]
+    fn parse_and_send_changesets_aggregates_min_max_id_and_created_at() {
+        let (tx, rx) = crossbeam_channel::bounded(8);
+        let filter = Filter::default();
+        let stats = parse_and_send_changesets(
+            SAMPLE_CHANGESETS_XML.as_bytes(),
+            &tx,
+            10,
+            false,
+            &filter,
+        )
+        .unwrap();
+        drop(tx);
+        for batch in rx.iter() {
+            drop(batch);
+        }
+
+        assert_eq!(stats.min_id, Some(1));
+        assert_eq!(stats.max_id, Some(3));
+        assert_eq!(stats.min_created_at, Some(parse_timestamp("2020-01-01T00:00:00Z").unwrap()));
+        assert_eq!(stats.max_created_at, Some(parse_timestamp("2020-01-03T00:00:00Z").unwrap()));
+    }
+
+    #[test]
+    fn parse_and_send_changesets_excludes_skipped_changesets_from_aggregates() {
+        let (tx, rx) = crossbeam_channel::bounded(8);
+        let filter = Filter {
+            min_id: Some(2),
+            ..Default::default()
+        };
+        let stats = parse_and_send_changesets(
+            SAMPLE_CHANGESETS_XML.as_bytes(),
+            &tx,
+            10,
+            false,
+            &filter,
+        )
+        .unwrap();
+        drop(tx);
+        for batch in rx.iter() {
+            drop(batch);
+        }
+
+        // Changeset 1 is filtered out, so it must not pull min_id/min_created_at down.
+        assert_eq!(stats.min_id, Some(2));
+        assert_eq!(stats.max_id, Some(3));
+        assert_eq!(stats.min_created_at, Some(parse_timestamp("2020-01-02T00:00:00Z").unwrap()));
+        assert_eq!(stats.max_created_at, Some(parse_timestamp("2020-01-03T00:00:00Z").unwrap()));
+    }
+
+    #[test]
+    fn partition_key_for_changeset_buckets_by_year() {
+        let cs = changeset(1, vec![]);
+        let cs = Changeset {
+            created_at: Some(parse_timestamp("2020-06-15T00:00:00Z").unwrap()),
+            ..cs
+        };
+        assert_eq!(
+            PartitionKey::for_changeset(PartitionBy::Year, &cs),
+            PartitionKey::Year(2020)
+        );
+    }
+
+    #[test]
+    fn partition_key_for_changeset_buckets_by_month() {
+        let cs = Changeset {
+            created_at: Some(parse_timestamp("2020-06-15T00:00:00Z").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            PartitionKey::for_changeset(PartitionBy::Month, &cs),
+            PartitionKey::YearMonth(2020, 6)
+        );
+    }
+
+    #[test]
+    fn partition_key_for_changeset_falls_back_to_null_when_created_at_missing() {
+        let cs = Changeset::default();
+        assert_eq!(
+            PartitionKey::for_changeset(PartitionBy::Year, &cs),
+            PartitionKey::Null
+        );
+        assert_eq!(
+            PartitionKey::for_changeset(PartitionBy::Month, &cs),
+            PartitionKey::Null
+        );
+    }
+
+    #[test]
+    fn partition_key_dir_name_formats_each_variant() {
+        assert_eq!(PartitionKey::Year(2020).dir_name(), "created_at_year=2020");
+        assert_eq!(
+            PartitionKey::YearMonth(2020, 6).dir_name(),
+            "created_at_year=2020/created_at_month=06"
+        );
+        assert_eq!(
+            PartitionKey::YearMonth(2020, 12).dir_name(),
+            "created_at_year=2020/created_at_month=12"
+        );
+        assert_eq!(PartitionKey::Null.dir_name(), "created_at_year=__null__");
+    }
+
+    #[test]
+    fn write_csv_field_passes_plain_fields_through_unquoted() {
+        let mut out = Vec::new();
+        write_csv_field(&mut out, "Bing iD").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Bing iD");
+    }
+
+    #[test]
+    fn write_csv_field_quotes_and_escapes_fields_needing_it() {
+        for (input, expected) in [
+            (r#"say "hi""#, "\"say \"\"hi\"\"\""),
+            ("a,b", "\"a,b\""),
+            ("line1\nline2", "\"line1\nline2\""),
+            ("line1\rline2", "\"line1\rline2\""),
+        ] {
+            let mut out = Vec::new();
+            write_csv_field(&mut out, input).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn changeset_to_json_omits_none_optional_fields() {
+        let cs = changeset(1, vec![]);
+        let json = changeset_to_json(&cs);
+        let obj = json.as_object().unwrap();
+
+        assert!(!obj.contains_key("created_at"));
+        assert!(!obj.contains_key("closed_at"));
+        assert!(!obj.contains_key("user"));
+        assert!(!obj.contains_key("uid"));
+        assert!(!obj.contains_key("min_lat"));
+        assert!(!obj.contains_key("min_lon"));
+        assert!(!obj.contains_key("max_lat"));
+        assert!(!obj.contains_key("max_lon"));
+        assert!(!obj.contains_key("description"));
+    }
+
+    #[test]
+    fn changeset_to_json_always_includes_tags_even_when_empty() {
+        let untagged = changeset_to_json(&changeset(1, vec![]));
+        assert_eq!(untagged["tags"], serde_json::json!({}));
+
+        let tagged = changeset_to_json(&changeset(1, vec![("source", "Bing")]));
+        assert_eq!(tagged["tags"], serde_json::json!({"source": "Bing"}));
+    }
+
+    #[test]
+    fn uid_or_user_filter_matches_either_allow_list() {
+        let filter = Filter {
+            uids: [1].into_iter().collect(),
+            users: ["alice".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+
+        let by_uid = Changeset {
+            uid: Some(1),
+            ..Default::default()
+        };
+        let by_user = Changeset {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let neither = Changeset {
+            uid: Some(2),
+            user: Some("bob".to_string()),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&by_uid));
+        assert!(filter.matches(&by_user));
+        assert!(!filter.matches(&neither));
+    }
+}